@@ -1,22 +1,34 @@
 #![feature(once_cell)]
 
 use std::{
+    collections::HashMap,
     sync::{Arc, OnceLock, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike};
 use embedded_svc::{
-    mqtt::client::Event,
+    http::{client::Client as HttpClient, Method},
+    io::{Read, Write},
+    mqtt::client::{Event, QoS},
     wifi::{AccessPointConfiguration, ClientConfiguration, Configuration},
 };
 use esp_idf_hal::{
-    gpio::{Output, OutputPin, PinDriver},
+    gpio::{Gpio22, Gpio23, Output, PinDriver},
     peripheral,
     prelude::*,
 };
 use esp_idf_svc::{
+    espnow::{EspNow, PeerInfo},
     eventloop::EspSystemEventLoop,
+    http::{
+        client::{Configuration as HttpClientConfiguration, EspHttpConnection},
+        server::{Configuration as HttpServerConfiguration, EspHttpServer},
+    },
     mqtt::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration},
+    nvs::{EspDefaultNvs, EspDefaultNvsPartition},
+    ota::{EspFirmwareInfoLoader, EspOta},
+    sntp::{EspSntp, SyncStatus},
     wifi::{BlockingWifi, EspWifi, WifiDeviceId},
 };
 use esp_idf_sys::{self as _, EspError}; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
@@ -27,9 +39,497 @@ use config::APP_CONFIG;
 
 static TOPIC_PREFIX: OnceLock<Option<String>> = OnceLock::new();
 const STEPS_TO_FULLY_OPEN: i16 = 4600;
+// Stepper acceleration profile, in steps/s^2 and steps/s respectively.
+const ACCEL_STEPS_PER_S2: f32 = 2000.0;
+const MAX_SPEED_STEPS_PER_S: f32 = 1600.0;
 static CURRENT_POSITION: once_cell::sync::Lazy<Arc<Mutex<f32>>> =
     once_cell::sync::Lazy::new(|| Arc::new(Mutex::new(0.0)));
 
+type StepPin = PinDriver<'static, Gpio22, Output>;
+type DirectionPin = PinDriver<'static, Gpio23, Output>;
+// Shared so both the MQTT callback and the scheduler loop can drive the motor.
+static MOTOR: OnceLock<Mutex<(StepPin, DirectionPin)>> = OnceLock::new();
+
+const NVS_NAMESPACE: &str = "curtains";
+const NVS_POSITION_KEY: &str = "position";
+const NVS_SCHEDULE_KEY: &str = "schedule";
+const MAX_SCHEDULE_LEN: usize = 512;
+static NVS: OnceLock<Mutex<EspDefaultNvs>> = OnceLock::new();
+
+const NVS_WIFI_SSID_KEY: &str = "wifi_ssid";
+const NVS_WIFI_PASSWORD_KEY: &str = "wifi_password";
+const MAX_WIFI_CREDENTIAL_LEN: usize = 64;
+const WIFI_CONNECT_RETRIES: u8 = 5;
+const PROVISIONING_AP_SSID: &str = "curtain-setup";
+
+/// How long to wait for SNTP to sync the clock before giving up and booting without one.
+const SNTP_SYNC_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A single scheduled move: fire on any weekday set in `weekday_mask` (bit 0 = Monday,
+/// bit 6 = Sunday) at `hour:minute` local time, moving to `position` (0.0-1.0).
+#[derive(Clone, Copy, Debug)]
+struct ScheduleEntry {
+    weekday_mask: u8,
+    hour: u8,
+    minute: u8,
+    position: f32,
+}
+
+impl ScheduleEntry {
+    fn is_due(&self, now: DateTime<Local>) -> bool {
+        let weekday_bit = 1u8 << now.weekday().num_days_from_monday();
+        self.weekday_mask & weekday_bit != 0
+            && now.hour() as u8 == self.hour
+            && now.minute() as u8 == self.minute
+    }
+}
+
+static SCHEDULE: OnceLock<Mutex<Vec<ScheduleEntry>>> = OnceLock::new();
+// Tracks the last date each schedule entry fired on, so the ~10s loop doesn't re-trigger
+// the same entry more than once per matching minute.
+static SCHEDULE_LAST_FIRED: OnceLock<Mutex<HashMap<usize, NaiveDate>>> = OnceLock::new();
+// Set once SNTP confirms a sync. If we gave up waiting on it at boot, `Local::now()` can't
+// be trusted, so the scheduler stays disabled rather than firing entries at the wrong time.
+static TIME_SYNCED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// Held so the OTA handler (started from the MQTT receive callback, running on its own thread)
+// and the status publishes it sends can both reach the same client the main loop publishes
+// position on.
+static MQTT_CLIENT: OnceLock<Mutex<EspMqttClient<'static>>> = OnceLock::new();
+
+/// Parse the `/schedule` MQTT/NVS payload: one entry per line, `weekday_mask,HH:MM,position`.
+fn parse_schedule(payload: &str) -> Vec<ScheduleEntry> {
+    payload
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() {
+                return None;
+            }
+            let mut parts = line.split(',');
+            let weekday_mask: u8 = parts.next()?.trim().parse().ok()?;
+            let (hour, minute) = parts.next()?.trim().split_once(':')?;
+            let hour: u8 = hour.parse().ok()?;
+            let minute: u8 = minute.parse().ok()?;
+            let position: f32 = parts.next()?.trim().parse().ok()?;
+            Some(ScheduleEntry {
+                weekday_mask,
+                hour,
+                minute,
+                position,
+            })
+        })
+        .collect()
+}
+
+fn serialize_schedule(entries: &[ScheduleEntry]) -> String {
+    entries
+        .iter()
+        .map(|e| format!("{},{:02}:{:02},{}", e.weekday_mask, e.hour, e.minute, e.position))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Check the schedule against the current local time and fire any due entries.
+fn run_scheduler() {
+    if !TIME_SYNCED.load(std::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
+
+    let schedule = SCHEDULE.get().unwrap().lock().unwrap().clone();
+    if schedule.is_empty() {
+        return;
+    }
+
+    let now = Local::now();
+    let today = now.date_naive();
+    let mut last_fired = SCHEDULE_LAST_FIRED.get().unwrap().lock().unwrap();
+
+    for (idx, entry) in schedule.iter().enumerate() {
+        if !entry.is_due(now) || last_fired.get(&idx) == Some(&today) {
+            continue;
+        }
+
+        info!(
+            "schedule entry {} is due, setting position to {}",
+            idx, entry.position
+        );
+        set_position(entry.position);
+        last_fired.insert(idx, today);
+    }
+}
+
+/// Publish a status string to `{topic_prefix}/ota/status`, best-effort.
+fn publish_ota_status(status: &str) {
+    let Some(topic_prefix) = TOPIC_PREFIX.get().and_then(|p| p.as_ref()) else {
+        return;
+    };
+    let topic = format!("{}/ota/status", topic_prefix);
+
+    if let Some(client) = MQTT_CLIENT.get() {
+        if let Err(why) = client
+            .lock()
+            .unwrap()
+            .publish(&topic, QoS::AtLeastOnce, false, status.as_bytes())
+        {
+            error!("failed to publish OTA status: {:?}", why);
+        }
+    }
+}
+
+/// Stream the firmware image at `url` into the next OTA partition, verify it, and mark it
+/// valid. Does not reboot; the caller decides what to do once the image is staged.
+fn stream_ota_update(url: &str) -> anyhow::Result<()> {
+    let connection = EspHttpConnection::new(&HttpClientConfiguration {
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut http_client = HttpClient::wrap(connection);
+    let request = http_client.get(url)?;
+    let mut response = request.submit()?;
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut info_loader = EspFirmwareInfoLoader::new();
+
+    let mut buf = [0u8; 1024];
+    let mut total_bytes = 0usize;
+    loop {
+        let n = response.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        info_loader.load(&buf[..n])?;
+        update.write(&buf[..n])?;
+        total_bytes += n;
+    }
+    info!("wrote {} bytes of OTA firmware", total_bytes);
+
+    let info = info_loader.get_info()?;
+    info!("received firmware info: {:?}", info);
+
+    update.complete()?;
+    Ok(())
+}
+
+/// Handle an `/ota` MQTT message: download and stage the firmware at `url`, then reboot
+/// into it. Progress and failures are reported on `{topic_prefix}/ota/status`. Runs on its
+/// own thread, since the download+flash can take minutes and must not block the MQTT
+/// client's receive-callback thread (which also has to keep servicing keepalive pings).
+fn perform_ota_update(url: String) {
+    std::thread::spawn(move || {
+        publish_ota_status("starting");
+
+        if let Err(why) = stream_ota_update(&url) {
+            error!("OTA update failed: {:?}", why);
+            publish_ota_status(&format!("failed: {:?}", why));
+            return;
+        }
+
+        publish_ota_status("success, rebooting");
+        unsafe {
+            esp_idf_sys::esp_restart();
+        }
+    });
+}
+
+const INDEX_HTML: &str = r#"<!doctype html>
+<html>
+<head><title>curtains</title></head>
+<body>
+  <h1>curtains</h1>
+  <p>position: <span id="position">?</span></p>
+  <input id="position-input" type="number" min="0" max="1" step="0.01">
+  <button onclick="setPosition()">set position</button>
+  <script>
+    async function refresh() {
+      const res = await fetch('/position');
+      document.getElementById('position').innerText = await res.text();
+    }
+    async function setPosition() {
+      const value = document.getElementById('position-input').value;
+      await fetch('/position', { method: 'POST', body: value });
+      refresh();
+    }
+    refresh();
+  </script>
+</body>
+</html>"#;
+
+/// Serve a small control panel and REST API on the device's STA IP, so the curtain stays
+/// controllable even when the MQTT broker is unreachable.
+fn start_http_server() -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpServerConfiguration::default())?;
+
+    server.fn_handler("/", Method::Get, |request| {
+        let mut response = request.into_ok_response()?;
+        response.write_all(INDEX_HTML.as_bytes())
+    })?;
+
+    server.fn_handler("/position", Method::Get, |request| {
+        let position = *CURRENT_POSITION.lock().unwrap();
+        let mut response = request.into_ok_response()?;
+        response.write_all(format!("{}", position).as_bytes())
+    })?;
+
+    server.fn_handler("/position", Method::Post, |mut request| {
+        let mut buf = [0u8; 32];
+        let len = request.read(&mut buf)?;
+        match std::str::from_utf8(&buf[..len]).ok().and_then(|s| s.trim().parse::<f32>().ok()) {
+            Some(position) => {
+                set_position(position);
+                request.into_ok_response()?;
+            }
+            None => {
+                request.into_status_response(400)?;
+            }
+        }
+        Ok(())
+    })?;
+
+    server.fn_handler("/step", Method::Post, |mut request| {
+        let mut buf = [0u8; 32];
+        let len = request.read(&mut buf)?;
+        match std::str::from_utf8(&buf[..len]).ok().and_then(|s| s.trim().parse::<i16>().ok()) {
+            Some(steps) => {
+                step_motor(steps);
+                request.into_ok_response()?;
+            }
+            None => {
+                request.into_status_response(400)?;
+            }
+        }
+        Ok(())
+    })?;
+
+    server.fn_handler("/home", Method::Post, |request| {
+        homing_sequence();
+        request.into_ok_response()?;
+        Ok(())
+    })?;
+
+    Ok(server)
+}
+
+// Fixed-layout ESP-NOW packet: opcode byte followed by its payload.
+const ESPNOW_OPCODE_SET_POSITION: u8 = 0;
+const ESPNOW_OPCODE_STEP: u8 = 1;
+const ESPNOW_OPCODE_PAIR_REQUEST: u8 = 2;
+const ESPNOW_BROADCAST_ADDR: [u8; 6] = [0xff; 6];
+// Local master key the paired remote's unicast link is encrypted with. In a real fleet
+// this would be per-device and provisioned alongside the WiFi credentials; a fixed key
+// still keeps commands on the paired link from travelling in the clear.
+const ESPNOW_LMK: [u8; 16] = *b"curtain-lmk-key!";
+const ESPNOW_PAIRING_WINDOW: Duration = Duration::from_secs(30);
+
+static ESPNOW: OnceLock<EspNow<'static>> = OnceLock::new();
+// Set once a remote completes the pairing handshake; after that, only commands whose
+// sender address is this MAC are accepted (unicast vs broadcast is not checked).
+static ESPNOW_PAIRED_PEER: OnceLock<Mutex<Option<[u8; 6]>>> = OnceLock::new();
+// Some(deadline) while a broadcast pairing request is being accepted.
+static ESPNOW_PAIRING_DEADLINE: OnceLock<Mutex<Option<Instant>>> = OnceLock::new();
+
+/// Open a pairing window: for the next `ESPNOW_PAIRING_WINDOW`, a broadcast pairing
+/// request from a new remote is accepted and paired. Triggered by the `/pair` MQTT topic,
+/// since there's no other trusted signal (like a physical button) to gate this on here.
+fn start_espnow_pairing() {
+    let Some(espnow) = ESPNOW.get() else {
+        return;
+    };
+
+    if let Err(why) = espnow.add_peer(PeerInfo {
+        peer_addr: ESPNOW_BROADCAST_ADDR,
+        channel: 0,
+        encrypt: false,
+        ..Default::default()
+    }) {
+        error!("ESP-NOW: failed to open pairing window: {:?}", why);
+        return;
+    }
+
+    *ESPNOW_PAIRING_DEADLINE.get().unwrap().lock().unwrap() =
+        Some(Instant::now() + ESPNOW_PAIRING_WINDOW);
+    info!("ESP-NOW: pairing window open for {:?}", ESPNOW_PAIRING_WINDOW);
+}
+
+fn espnow_pairing_is_open() -> bool {
+    matches!(*ESPNOW_PAIRING_DEADLINE.get().unwrap().lock().unwrap(), Some(deadline) if Instant::now() < deadline)
+}
+
+/// Close an expired pairing window, if one is open. Call this periodically (the main
+/// loop's ~10s tick) so the broadcast peer doesn't stay accepted indefinitely.
+fn expire_espnow_pairing() {
+    let mut deadline = ESPNOW_PAIRING_DEADLINE.get().unwrap().lock().unwrap();
+    let Some(by) = *deadline else {
+        return;
+    };
+    if Instant::now() < by {
+        return;
+    }
+
+    *deadline = None;
+    if let Some(espnow) = ESPNOW.get() {
+        let _ = espnow.del_peer(&ESPNOW_BROADCAST_ADDR);
+    }
+    info!("ESP-NOW: pairing window closed");
+}
+
+/// Register `mac` as the paired remote, encrypting its unicast link with `ESPNOW_LMK`,
+/// and stop accepting the broadcast pairing request.
+fn pair_espnow_peer(mac: [u8; 6]) -> anyhow::Result<()> {
+    let espnow = ESPNOW.get().ok_or_else(|| anyhow::anyhow!("ESP-NOW not initialized"))?;
+
+    if espnow.peer_exists(&ESPNOW_BROADCAST_ADDR)? {
+        espnow.del_peer(&ESPNOW_BROADCAST_ADDR)?;
+    }
+
+    espnow.add_peer(PeerInfo {
+        peer_addr: mac,
+        lmk: ESPNOW_LMK,
+        channel: 0,
+        encrypt: true,
+        ..Default::default()
+    })?;
+
+    *ESPNOW_PAIRED_PEER.get().unwrap().lock().unwrap() = Some(mac);
+    *ESPNOW_PAIRING_DEADLINE.get().unwrap().lock().unwrap() = None;
+
+    Ok(())
+}
+
+/// Dispatch a raw ESP-NOW packet (opcode byte + payload) straight to the motor, bypassing
+/// WiFi/MQTT entirely. Only a pairing request during an open pairing window, or a command
+/// whose sender address is the already-paired peer, is ever acted on; whether the packet
+/// was unicast or broadcast is not checked.
+fn handle_espnow_packet(mac_addr: &[u8], data: &[u8]) {
+    let Ok(mac) = <[u8; 6]>::try_from(mac_addr) else {
+        error!("ESP-NOW: malformed sender address: {:?}", mac_addr);
+        return;
+    };
+
+    if data.first() == Some(&ESPNOW_OPCODE_PAIR_REQUEST) {
+        if !espnow_pairing_is_open() {
+            error!("ESP-NOW: pairing request from {:?} outside of pairing window, ignoring", mac);
+            return;
+        }
+
+        if let Err(why) = pair_espnow_peer(mac) {
+            error!("ESP-NOW: failed to pair with {:?}: {:?}", mac, why);
+            return;
+        }
+
+        info!("ESP-NOW: paired with remote {:?}", mac);
+        return;
+    }
+
+    if *ESPNOW_PAIRED_PEER.get().unwrap().lock().unwrap() != Some(mac) {
+        error!("ESP-NOW: ignoring command from unpaired sender {:?}", mac);
+        return;
+    }
+
+    match data {
+        [ESPNOW_OPCODE_SET_POSITION, rest @ ..] if rest.len() >= 4 => {
+            let position = f32::from_le_bytes(rest[..4].try_into().unwrap());
+            if !position.is_finite() {
+                error!("ESP-NOW: rejecting non-finite position {}", position);
+                return;
+            }
+            let position = position.clamp(0.0, 1.0);
+            info!("ESP-NOW: set position to {}", position);
+            set_position(position);
+        }
+        [ESPNOW_OPCODE_STEP, rest @ ..] if rest.len() >= 2 => {
+            let steps = i16::from_le_bytes(rest[..2].try_into().unwrap())
+                .clamp(-STEPS_TO_FULLY_OPEN, STEPS_TO_FULLY_OPEN);
+            info!("ESP-NOW: stepping {}", steps);
+            step_motor(steps);
+        }
+        _ => {
+            error!("ESP-NOW: malformed or unknown packet: {:?}", data);
+        }
+    }
+}
+
+/// Bring up ESP-NOW so a paired remote can drive the curtain directly, without going
+/// through the router or MQTT broker. No peer is accepted until a pairing window is
+/// explicitly opened (see `start_espnow_pairing`).
+fn start_espnow() -> anyhow::Result<()> {
+    let espnow = EspNow::take()?;
+    espnow.register_recv_cb(|mac_addr, data| handle_espnow_packet(mac_addr, data))?;
+
+    ESPNOW_PAIRED_PEER.set(Mutex::new(None)).unwrap();
+    ESPNOW_PAIRING_DEADLINE.set(Mutex::new(None)).unwrap();
+    ESPNOW
+        .set(espnow)
+        .map_err(|_| anyhow::anyhow!("ESP-NOW already initialized"))?;
+
+    Ok(())
+}
+
+/// Read the last persisted position back from NVS, if one was ever saved.
+fn load_position(nvs: &EspDefaultNvs) -> Option<f32> {
+    let mut buf = [0u8; 4];
+    match nvs.get_raw(NVS_POSITION_KEY, &mut buf) {
+        Ok(Some(bytes)) if bytes.len() == 4 => Some(f32::from_le_bytes(bytes.try_into().unwrap())),
+        Ok(_) => None,
+        Err(why) => {
+            error!("failed to read persisted position from nvs: {:?}", why);
+            None
+        }
+    }
+}
+
+/// Persist the current position so it survives a reboot. Call this on completion of a
+/// move, not per micro-step, to keep flash wear reasonable.
+fn save_position(nvs: &mut EspDefaultNvs, position: f32) {
+    if let Err(why) = nvs.set_raw(NVS_POSITION_KEY, &position.to_le_bytes()) {
+        error!("failed to persist position to nvs: {:?}", why);
+    }
+}
+
+/// Read the persisted schedule back from NVS, if one was ever saved.
+fn load_schedule(nvs: &EspDefaultNvs) -> Vec<ScheduleEntry> {
+    let mut buf = [0u8; MAX_SCHEDULE_LEN];
+    match nvs.get_str(NVS_SCHEDULE_KEY, &mut buf) {
+        Ok(Some(payload)) => parse_schedule(payload),
+        Ok(None) => Vec::new(),
+        Err(why) => {
+            error!("failed to read persisted schedule from nvs: {:?}", why);
+            Vec::new()
+        }
+    }
+}
+
+fn save_schedule(nvs: &mut EspDefaultNvs, entries: &[ScheduleEntry]) {
+    if let Err(why) = nvs.set_str(NVS_SCHEDULE_KEY, &serialize_schedule(entries)) {
+        error!("failed to persist schedule to nvs: {:?}", why);
+    }
+}
+
+/// Read WiFi credentials provisioned over the captive portal, if any were ever saved.
+fn load_wifi_credentials(nvs: &EspDefaultNvs) -> Option<(String, String)> {
+    let mut ssid_buf = [0u8; MAX_WIFI_CREDENTIAL_LEN];
+    let mut password_buf = [0u8; MAX_WIFI_CREDENTIAL_LEN];
+
+    let ssid = nvs.get_str(NVS_WIFI_SSID_KEY, &mut ssid_buf).ok().flatten()?;
+    let password = nvs
+        .get_str(NVS_WIFI_PASSWORD_KEY, &mut password_buf)
+        .ok()
+        .flatten()?;
+
+    Some((ssid.to_string(), password.to_string()))
+}
+
+fn save_wifi_credentials(nvs: &mut EspDefaultNvs, ssid: &str, password: &str) {
+    if let Err(why) = nvs.set_str(NVS_WIFI_SSID_KEY, ssid) {
+        error!("failed to persist wifi ssid to nvs: {:?}", why);
+    }
+    if let Err(why) = nvs.set_str(NVS_WIFI_PASSWORD_KEY, password) {
+        error!("failed to persist wifi password to nvs: {:?}", why);
+    }
+}
+
 fn main() {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
@@ -45,16 +545,25 @@ fn main() {
 
     info!("config: {:?}", &APP_CONFIG);
 
+    let nvs_partition = EspDefaultNvsPartition::take().unwrap();
+    let mut nvs = EspDefaultNvs::new(nvs_partition, NVS_NAMESPACE, true).unwrap();
+    let persisted_position = load_position(&nvs);
+    SCHEDULE.set(Mutex::new(load_schedule(&nvs))).unwrap();
+    SCHEDULE_LAST_FIRED.set(Mutex::new(HashMap::new())).unwrap();
+
     // setup pins
     let mut led_pin = PinDriver::output(peripherals.pins.gpio2).unwrap();
 
-    let mut step_pin = PinDriver::output(peripherals.pins.gpio22).unwrap();
-    let mut direction_pin = PinDriver::output(peripherals.pins.gpio23).unwrap();
+    let step_pin = PinDriver::output(peripherals.pins.gpio22).unwrap();
+    let direction_pin = PinDriver::output(peripherals.pins.gpio23).unwrap();
+    MOTOR.set(Mutex::new((step_pin, direction_pin))).unwrap();
 
     led_pin.set_high().unwrap();
 
-    // connect to wifi
+    // connect to wifi with the credentials stored in nvs, or the build-time ones if none are
+    // stored; the captive portal is only started if connecting with those fails
     let wifi = wifi(
+        &mut nvs,
         APP_CONFIG.wifi_ssid,
         APP_CONFIG.wifi_password,
         peripherals.modem,
@@ -62,18 +571,48 @@ fn main() {
     )
     .unwrap();
 
+    NVS.set(Mutex::new(nvs)).unwrap();
+
+    info!("initializing ESP-NOW");
+    start_espnow().unwrap();
+
+    info!("Syncing time via SNTP...");
+    let sntp = EspSntp::new_default().unwrap();
+    let sntp_deadline = Instant::now() + SNTP_SYNC_TIMEOUT;
+    while sntp.get_sync_status() != SyncStatus::Completed {
+        if Instant::now() >= sntp_deadline {
+            error!(
+                "SNTP did not sync within {:?}, booting without a synced clock (schedule disabled)",
+                SNTP_SYNC_TIMEOUT
+            );
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    if sntp.get_sync_status() == SyncStatus::Completed {
+        TIME_SYNCED.store(true, std::sync::atomic::Ordering::Relaxed);
+        info!("Time synced: {}", Local::now());
+    }
+
     // mqtt configuration
     let broker_url = format!(
         "mqtt://{}:{}@{}",
         APP_CONFIG.mqtt_username, APP_CONFIG.mqtt_password, APP_CONFIG.mqtt_host
     );
     let mqtt_config = MqttClientConfiguration::default();
-    homing_sequence(&mut step_pin, &mut direction_pin);
 
-    let mut mqtt_client = EspMqttClient::new(broker_url, &mqtt_config, move |message| {
-        on_message_received(message, &mut step_pin, &mut direction_pin)
-    })
-    .unwrap();
+    if let Some(position) = persisted_position {
+        info!("found persisted position {}, skipping homing sequence", position);
+        *CURRENT_POSITION.lock().unwrap() = position;
+    } else {
+        homing_sequence();
+    }
+
+    let mqtt_client = EspMqttClient::new(broker_url, &mqtt_config, on_message_received).unwrap();
+    MQTT_CLIENT.set(Mutex::new(mqtt_client)).unwrap();
+
+    info!("starting fallback HTTP control panel");
+    let _http_server = start_http_server().unwrap();
 
     // get mac address
     let mac_address = wifi
@@ -95,8 +634,12 @@ fn main() {
     let topic = format!("{}/#", &topic_prefix);
     info!("subscribing to topic {}", topic);
 
-    mqtt_client
-        .subscribe(&topic, embedded_svc::mqtt::client::QoS::AtLeastOnce)
+    MQTT_CLIENT
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .subscribe(&topic, QoS::AtLeastOnce)
         .unwrap();
 
     // turn off led when connected to everything successfully
@@ -105,20 +648,25 @@ fn main() {
     loop {
         std::thread::sleep(Duration::from_secs(10));
 
-        mqtt_client.publish(
-            format!("{}/position", &topic_prefix).as_str(),
-            embedded_svc::mqtt::client::QoS::AtLeastOnce,
-            false,
-            format!("{}", CURRENT_POSITION.lock().unwrap()).as_bytes(),
-        ).unwrap();
+        run_scheduler();
+        expire_espnow_pairing();
+
+        MQTT_CLIENT
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .publish(
+                format!("{}/position", &topic_prefix).as_str(),
+                QoS::AtLeastOnce,
+                false,
+                format!("{}", CURRENT_POSITION.lock().unwrap()).as_bytes(),
+            )
+            .unwrap();
     }
 }
 
-fn on_message_received<T: OutputPin, U: OutputPin>(
-    message: &std::result::Result<Event<EspMqttMessage>, EspError>,
-    step_pin: &mut PinDriver<T, Output>,
-    direction_pin: &mut PinDriver<U, Output>,
-) {
+fn on_message_received(message: &std::result::Result<Event<EspMqttMessage>, EspError>) {
     match message {
         Ok(Event::Received(message)) => {
             info!("Received message: {:?}", message);
@@ -133,14 +681,32 @@ fn on_message_received<T: OutputPin, U: OutputPin>(
                     let payload = String::from_utf8(message.data().to_vec()).unwrap();
                     let steps: i16 = payload.parse().unwrap();
 
-                    // let steps: i16 = payload.parse().unwrap();
-                    step_motor(step_pin, direction_pin, steps);
+                    step_motor(steps);
                 }
                 "/set-position" => {
                     let payload = String::from_utf8(message.data().to_vec()).unwrap();
                     let position: f32 = payload.parse().unwrap();
 
-                    set_position(step_pin, direction_pin, position);
+                    set_position(position);
+                }
+                "/schedule" => {
+                    let payload = String::from_utf8(message.data().to_vec()).unwrap();
+                    let entries = parse_schedule(&payload);
+                    info!("updating schedule with {} entries", entries.len());
+
+                    if let Some(nvs) = NVS.get() {
+                        save_schedule(&mut nvs.lock().unwrap(), &entries);
+                    }
+                    *SCHEDULE.get().unwrap().lock().unwrap() = entries;
+                    SCHEDULE_LAST_FIRED.get().unwrap().lock().unwrap().clear();
+                }
+                "/ota" => {
+                    let url = String::from_utf8(message.data().to_vec()).unwrap();
+                    info!("starting OTA update from {}", url);
+                    perform_ota_update(url);
+                }
+                "/pair" => {
+                    start_espnow_pairing();
                 }
                 _ => {
                     error!("Unknown topic: {:?}", topic);
@@ -159,12 +725,42 @@ fn on_message_received<T: OutputPin, U: OutputPin>(
     }
 }
 
-fn step_motor<T: OutputPin, U: OutputPin>(
-    step_pin: &mut PinDriver<T, Output>,
-    direction_pin: &mut PinDriver<U, Output>,
-    steps: i16,
-) {
-    let step_delay = Duration::from_micros(700);
+/// Build the per-step delays for a trapezoidal speed profile: accelerate from rest up to
+/// `max_speed`, cruise, then mirror the acceleration back down to rest. Uses the AVR446
+/// recurrence `c_n = c_{n-1} - (2*c_{n-1})/(4*n + 1)` starting from `c0 = 0.676 * sqrt(2/accel)`.
+/// Moves too short to reach cruise speed switch to deceleration at the midpoint.
+fn trapezoidal_ramp(total_steps: u32, accel: f32, max_speed: f32) -> Vec<Duration> {
+    if total_steps == 0 {
+        return Vec::new();
+    }
+
+    let c_min = 1_000_000.0 / max_speed; // delay in us once max speed is reached
+    let mut c = 0.676 * (2.0 / accel).sqrt() * 1_000_000.0; // c0, in us
+
+    let half = total_steps / 2;
+    let mut accel_delays = Vec::new();
+    let mut n = 1.0_f32;
+    while accel_delays.len() < half as usize && c > c_min {
+        accel_delays.push(Duration::from_micros(c as u64));
+        c -= (2.0 * c) / (4.0 * n + 1.0);
+        n += 1.0;
+    }
+
+    let cruise_steps = total_steps - 2 * accel_delays.len() as u32;
+    let mut delays = Vec::with_capacity(total_steps as usize);
+    delays.extend(accel_delays.iter().copied());
+    // For a short move that never reached `c_min`, the odd middle step (if any) continues the
+    // ramp at `c` instead of jumping to max speed.
+    let cruise_delay = Duration::from_micros(c.max(c_min) as u64);
+    delays.extend(std::iter::repeat(cruise_delay).take(cruise_steps as usize));
+    delays.extend(accel_delays.iter().rev().copied());
+
+    delays
+}
+
+fn step_motor(steps: i16) {
+    let mut motor = MOTOR.get().unwrap().lock().unwrap();
+    let (step_pin, direction_pin) = &mut *motor;
 
     // positive is right, negative is left
     if steps > 0 {
@@ -173,11 +769,16 @@ fn step_motor<T: OutputPin, U: OutputPin>(
         direction_pin.set_low().unwrap();
     }
 
-    for _ in 0..steps.abs() {
+    // Each ramp entry is the full step period; split it across the high and low halves of
+    // the pulse so the real step rate matches `ACCEL_STEPS_PER_S2`/`MAX_SPEED_STEPS_PER_S`
+    // instead of running at half speed.
+    let ramp = trapezoidal_ramp(steps.unsigned_abs() as u32, ACCEL_STEPS_PER_S2, MAX_SPEED_STEPS_PER_S);
+    for step_delay in ramp {
+        let half_delay = step_delay / 2;
         step_pin.set_high().unwrap();
-        std::thread::sleep(step_delay);
+        std::thread::sleep(half_delay);
         step_pin.set_low().unwrap();
-        std::thread::sleep(step_delay);
+        std::thread::sleep(half_delay);
     }
     let mut current_position = CURRENT_POSITION.lock().unwrap();
 
@@ -185,14 +786,15 @@ fn step_motor<T: OutputPin, U: OutputPin>(
         (*current_position + steps as f32 / STEPS_TO_FULLY_OPEN as f32).clamp(0.0, 1.0);
     info!("current_position: {}, new position: {}", current_position, new_position);
     *current_position = new_position;
+    drop(current_position);
+
+    if let Some(nvs) = NVS.get() {
+        save_position(&mut nvs.lock().unwrap(), new_position);
+    }
 }
 
 /// Set the position of the curtains in terms of 0-1
-fn set_position<T: OutputPin, U: OutputPin>(
-    step_pin: &mut PinDriver<T, Output>,
-    direction_pin: &mut PinDriver<U, Output>,
-    position: f32,
-) {
+fn set_position(position: f32) {
     let current_position = CURRENT_POSITION.lock().unwrap();
     let current_position_as_steps = (*current_position * STEPS_TO_FULLY_OPEN as f32) as i16;
     drop(current_position);
@@ -203,13 +805,10 @@ fn set_position<T: OutputPin, U: OutputPin>(
         "setting position to {} using {} steps delta",
         position, delta_steps
     );
-    step_motor(step_pin, direction_pin, delta_steps);
+    step_motor(delta_steps);
 }
 
-fn homing_sequence<T: OutputPin, U: OutputPin>(
-    step_pin: &mut PinDriver<T, Output>,
-    direction_pin: &mut PinDriver<U, Output>,
-) {
+fn homing_sequence() {
     info!("running homing sequence");
 
     // TODO: this should use one/two limit switch(es)
@@ -217,32 +816,77 @@ fn homing_sequence<T: OutputPin, U: OutputPin>(
     // move left for STEPS_TO_FULLY_OPEN steps, this should open the curtain completely
     // the stepper driver does current limiting so it should be fine to just run it into the end
     // â€” still very unelegant and sounds horrible when it hits the too early
-    step_motor(step_pin, direction_pin, -STEPS_TO_FULLY_OPEN);
+    step_motor(-STEPS_TO_FULLY_OPEN);
 
     let mut current_position = CURRENT_POSITION.lock().unwrap();
     *current_position = 0.0;
+    drop(current_position);
+
+    if let Some(nvs) = NVS.get() {
+        save_position(&mut nvs.lock().unwrap(), 0.0);
+    }
 }
 
+/// Connect to WiFi, using credentials stored in NVS if present (falling back to the
+/// build-time `APP_CONFIG` ones otherwise) and provisioning fresh credentials over a
+/// captive portal if none work, so a unit can be deployed on a new network without
+/// reflashing.
 fn wifi(
-    ssid: &str,
-    password: &str,
+    nvs: &mut EspDefaultNvs,
+    default_ssid: &str,
+    default_password: &str,
     modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
 ) -> anyhow::Result<Box<EspWifi<'static>>> {
     let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
-
     let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
 
+    let (mut ssid, mut password) = load_wifi_credentials(nvs).unwrap_or_else(|| {
+        info!("no wifi credentials stored in nvs, trying the build-time config");
+        (default_ssid.to_string(), default_password.to_string())
+    });
+
+    while try_connect(&mut wifi, &ssid, &password).is_err() {
+        info!(
+            "could not connect to {}, starting provisioning portal on AP \"{}\"",
+            ssid, PROVISIONING_AP_SSID
+        );
+        let (new_ssid, new_password) = run_provisioning_portal(&mut wifi)?;
+        save_wifi_credentials(nvs, &new_ssid, &new_password);
+        ssid = new_ssid;
+        password = new_password;
+        // Don't `?` out of the loop here: if the submitted credentials also fail to
+        // connect (e.g. a mistyped password), we want to re-serve the portal instead of
+        // propagating the error up to main()'s `.unwrap()` and rebooting the device.
+    }
+
+    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    info!("Wifi DHCP info: {:?}", ip_info);
+
+    Ok(Box::new(esp_wifi))
+}
+
+/// Attempt to join `ssid`, making up to `WIFI_CONNECT_RETRIES` connection attempts before
+/// giving up.
+fn try_connect(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+    ssid: &str,
+    password: &str,
+) -> anyhow::Result<()> {
+    // The driver may already be running (e.g. still in AP mode from a prior provisioning
+    // portal run); `start()` errors out if called again without stopping first. It may
+    // also be the very first call, before the driver has ever been started, so only stop
+    // it if it's actually running.
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
     wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
 
     info!("Starting wifi...");
-
     wifi.start()?;
 
     info!("Scanning...");
-
     let ap_infos = wifi.scan()?;
-
     let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
 
     let channel = if let Some(ours) = ours {
@@ -267,23 +911,137 @@ fn wifi(
             ..Default::default()
         },
         AccessPointConfiguration {
-            ssid: "aptest".into(),
+            ssid: PROVISIONING_AP_SSID.into(),
             channel: channel.unwrap_or(1),
             ..Default::default()
         },
     ))?;
 
-    info!("Connecting wifi...");
+    for attempt in 1..=WIFI_CONNECT_RETRIES {
+        info!("Connecting wifi (attempt {}/{})...", attempt, WIFI_CONNECT_RETRIES);
 
-    wifi.connect()?;
+        if wifi.connect().is_ok() && wifi.wait_netif_up().is_ok() {
+            return Ok(());
+        }
 
-    info!("Waiting for DHCP lease...");
+        error!("wifi connect attempt {} failed", attempt);
+    }
 
-    wifi.wait_netif_up()?;
+    anyhow::bail!("failed to connect to {} after {} attempts", ssid, WIFI_CONNECT_RETRIES)
+}
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+/// Stay in AP mode and serve a page listing nearby networks; once the user submits an
+/// SSID/password over `/connect`, return them so the caller can persist and reconnect.
+fn run_provisioning_portal(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+) -> anyhow::Result<(String, String)> {
+    // `try_connect` already started the driver in Client/Mixed mode; stop it before
+    // reconfiguring, since `start()` on an already-started driver errors out. The STA side
+    // stays enabled next to the AP because ESP-IDF can only scan in STA or AP+STA mode.
+    wifi.stop()?;
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        AccessPointConfiguration {
+            ssid: PROVISIONING_AP_SSID.into(),
+            ..Default::default()
+        },
+    ))?;
+    wifi.start()?;
 
-    info!("Wifi DHCP info: {:?}", ip_info);
+    let networks = wifi.scan()?;
+    let page = provisioning_page(&networks);
 
-    Ok(Box::new(esp_wifi))
+    let credentials: Arc<Mutex<Option<(String, String)>>> = Arc::new(Mutex::new(None));
+    let submitted = credentials.clone();
+
+    let mut server = EspHttpServer::new(&HttpServerConfiguration::default())?;
+
+    server.fn_handler("/", Method::Get, move |request| {
+        let mut response = request.into_ok_response()?;
+        response.write_all(page.as_bytes())
+    })?;
+
+    server.fn_handler("/connect", Method::Post, move |mut request| {
+        let mut buf = [0u8; 256];
+        let len = request.read(&mut buf)?;
+        let body = std::str::from_utf8(&buf[..len]).unwrap_or("");
+
+        let mut ssid = None;
+        let mut password = None;
+        for pair in body.split('&') {
+            if let Some((key, value)) = pair.split_once('=') {
+                match key {
+                    "ssid" => ssid = Some(percent_decode(value)),
+                    "password" => password = Some(percent_decode(value)),
+                    _ => {}
+                }
+            }
+        }
+
+        match (ssid, password) {
+            (Some(ssid), Some(password)) => {
+                *submitted.lock().unwrap() = Some((ssid, password));
+                request.into_ok_response()?;
+            }
+            _ => {
+                request.into_status_response(400)?;
+            }
+        }
+        Ok(())
+    })?;
+
+    info!("provisioning portal listening, waiting for credentials...");
+
+    loop {
+        if let Some(creds) = credentials.lock().unwrap().take() {
+            drop(server);
+            return Ok(creds);
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn provisioning_page(networks: &[embedded_svc::wifi::AccessPointInfo]) -> String {
+    let options = networks
+        .iter()
+        .map(|ap| format!("<option value=\"{0}\">{0}</option>", ap.ssid))
+        .collect::<String>();
+
+    format!(
+        r#"<!doctype html>
+<html>
+<head><title>curtain wifi setup</title></head>
+<body>
+  <h1>connect curtain to wifi</h1>
+  <form method="POST" action="/connect">
+    <label>network</label>
+    <select name="ssid">{options}</select>
+    <br>
+    <label>password</label>
+    <input type="password" name="password">
+    <br>
+    <button type="submit">connect</button>
+  </form>
+</body>
+</html>"#,
+        options = options
+    )
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '+' => result.push(' '),
+            '%' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if let Ok(byte) = u8::from_str_radix(&hex, 16) {
+                    result.push(byte as char);
+                }
+            }
+            other => result.push(other),
+        }
+    }
+    result
 }